@@ -0,0 +1,137 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use layers::LayerBuffer;
+
+use geom::size::Size2D;
+use platform::surface::NativeCompositingGraphicsContext;
+
+use std::collections::HashMap;
+
+/// The default byte budget for recycled buffers. Once the total memory used by
+/// evicted-but-not-yet-reused buffers exceeds this, the least-recently-used
+/// ones are dropped instead of kept around for recycling.
+const DEFAULT_BUDGET: usize = 20_000_000;
+
+/// One size class's worth of recycled buffers, each tagged with the tick it
+/// was inserted at so `evict_until_under_budget` can compare recency across
+/// buckets. Within a bucket, `insert` always appends, so the last entry is
+/// always the most recently recycled one of that size.
+struct BufferBucket {
+    entries: Vec<(usize, Box<LayerBuffer>)>,
+}
+
+impl BufferBucket {
+    fn new() -> BufferBucket {
+        BufferBucket { entries: vec!() }
+    }
+
+    fn mem(&self) -> usize {
+        self.entries.iter().map(|&(_, ref buffer)| buffer.get_mem()).sum()
+    }
+}
+
+/// A cache of unused `LayerBuffer`s, keyed by pixel size, so that a tile that
+/// needs repainting can reuse an existing `NativeSurface` of matching size
+/// instead of allocating a new one. Buffers are evicted least-recently-used
+/// once the cache's total memory use exceeds its budget.
+pub struct BufferMap {
+    // Keyed by (width, height) rather than Size2D directly, since Size2D isn't
+    // guaranteed to implement Eq/Hash.
+    buckets: HashMap<(usize, usize), BufferBucket>,
+    budget: usize,
+
+    /// A monotonically increasing logical clock, used to order entries by
+    /// recency across buckets for eviction.
+    next_tick: usize,
+}
+
+impl BufferMap {
+    pub fn new(budget: usize) -> BufferMap {
+        BufferMap {
+            buckets: HashMap::new(),
+            budget: budget,
+            next_tick: 0,
+        }
+    }
+
+    /// Removes and returns the most recently recycled buffer of the given
+    /// size, if one is cached. Callers building `BufferRequest`s for a tile
+    /// should check this before assuming a fresh `NativeSurface` needs to be
+    /// allocated to satisfy it.
+    pub fn find(&mut self, size: Size2D<usize>) -> Option<Box<LayerBuffer>> {
+        match self.buckets.get_mut(&(size.width, size.height)) {
+            Some(bucket) => bucket.entries.pop().map(|(_, buffer)| buffer),
+            None => None,
+        }
+    }
+
+    /// Inserts `buffers` into the cache, then evicts the least-recently-used
+    /// buffers of any size until the cache is back under budget. Each buffer
+    /// is expected to have already had `mark_wont_leak` called by whatever
+    /// thread/context produced it (e.g. a `RenderPool` worker, immediately
+    /// after painting) -- that needs to happen on the owning context's
+    /// thread, which by the time a buffer reaches this cache is no longer
+    /// recoverable.
+    pub fn insert_buffers(&mut self,
+                           _display: &NativeCompositingGraphicsContext,
+                           buffers: Vec<Box<LayerBuffer>>) {
+        for buffer in buffers {
+            let size = buffer.get_size_2d();
+            let tick = self.next_tick;
+            self.next_tick += 1;
+            self.buckets.entry((size.width, size.height))
+                        .or_insert_with(BufferBucket::new)
+                        .entries.push((tick, buffer));
+        }
+
+        self.evict_until_under_budget();
+    }
+
+    fn total_mem(&self) -> usize {
+        self.buckets.values().map(|bucket| bucket.mem()).sum()
+    }
+
+    /// Drops the oldest buffer across all size buckets until the cache's total
+    /// memory use is back under `budget`. Eviction is real cross-size LRU: each
+    /// bucket's oldest entry (its front) is compared by insertion tick against
+    /// every other bucket's oldest entry, and the globally oldest one is
+    /// dropped, so one size class hogging the budget can't starve the others
+    /// out. `LayerBuffer::destroy` needs a `NativePaintingGraphicsContext` (the
+    /// painting task's context, not the compositor's), which isn't available
+    /// here, so an evicted buffer is just dropped; its `NativeSurface` was
+    /// already marked `mark_wont_leak` by its producer before it reached this
+    /// cache.
+    fn evict_until_under_budget(&mut self) {
+        while self.total_mem() > self.budget {
+            let oldest = self.buckets.iter()
+                                      .filter_map(|(size, bucket)| {
+                                          bucket.entries.first().map(|&(tick, _)| (tick, *size))
+                                      })
+                                      .min_by_key(|&(tick, _)| tick);
+
+            let size = match oldest {
+                Some((_, size)) => size,
+                None => break,
+            };
+
+            if let Some(bucket) = self.buckets.get_mut(&size) {
+                if !bucket.entries.is_empty() {
+                    bucket.entries.remove(0);
+                }
+            }
+        }
+    }
+}
+
+impl Default for BufferMap {
+    fn default() -> BufferMap {
+        BufferMap::new(DEFAULT_BUDGET)
+    }
+}
@@ -7,8 +7,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use buffer_map::BufferMap;
 use color::Color;
 use geometry::{DevicePixel, LayerPixel};
+use render_pool::{PaintFn, RenderPool};
 use tiling::{Tile, TileGrid};
 
 use geom::matrix::{Matrix4, identity};
@@ -18,7 +20,8 @@ use geom::point::{Point2D, TypedPoint2D};
 use geom::rect::{Rect, TypedRect};
 use platform::surface::{NativeCompositingGraphicsContext, NativePaintingGraphicsContext};
 use platform::surface::NativeSurface;
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
+use std::mem;
 use std::rc::Rc;
 
 #[derive(Clone, Copy, PartialEq, PartialOrd)]
@@ -38,10 +41,82 @@ impl ContentAge {
     }
 }
 
+/// A hash of a single piece of content — a primitive, color, or opacity value — that
+/// touches a tile's `page_rect`. Comparing a tile's current set of dependencies
+/// against the previous frame's tells us whether that specific tile needs to be
+/// repainted, without having to fall back on invalidating the whole layer.
+pub type Dependency = u64;
+
+/// The number of sub-rects a `DirtyRegion` tracks before collapsing to its
+/// bounding rect, trading a little overdraw for a bounded bookkeeping cost.
+const MAX_DIRTY_SUB_RECTS: usize = 16;
+
+/// A bounded set of rects that changed since it was last taken, used to drive
+/// partial-present or scissored composites instead of redrawing a whole layer every
+/// frame. Rects are stored as given, without merging overlapping ones. Once more
+/// than `MAX_DIRTY_SUB_RECTS` rects have accumulated, the region collapses to just
+/// its bounding rect.
+#[derive(Clone)]
+pub struct DirtyRegion {
+    rects: Vec<TypedRect<LayerPixel, f32>>,
+    bounding_rect: Option<TypedRect<LayerPixel, f32>>,
+
+    /// How many rects have been unioned in, even past the point where we stopped
+    /// storing them individually. Used to tell whether we've collapsed.
+    total_rects: usize,
+}
+
+impl DirtyRegion {
+    pub fn new() -> DirtyRegion {
+        DirtyRegion {
+            rects: vec!(),
+            bounding_rect: None,
+            total_rects: 0,
+        }
+    }
+
+    /// Adds `rect` to this region, coalescing it into the bounding rect and, unless
+    /// the region has already collapsed, recording it as its own sub-rect too.
+    pub fn union(&mut self, rect: TypedRect<LayerPixel, f32>) {
+        self.bounding_rect = Some(match self.bounding_rect {
+            Some(bounds) => bounds.union(&rect),
+            None => rect,
+        });
+
+        self.total_rects += 1;
+        if self.rects.len() < MAX_DIRTY_SUB_RECTS {
+            self.rects.push(rect);
+        }
+    }
+
+    fn is_collapsed(&self) -> bool {
+        self.total_rects > MAX_DIRTY_SUB_RECTS
+    }
+
+    /// Returns the sub-rects that make up this region (or just the
+    /// bounding rect, if it has collapsed), resetting the region to empty.
+    pub fn take(&mut self) -> Vec<TypedRect<LayerPixel, f32>> {
+        let region = mem::replace(self, DirtyRegion::new());
+        if region.is_collapsed() {
+            match region.bounding_rect {
+                Some(rect) => vec!(rect),
+                None => vec!(),
+            }
+        } else {
+            region.rects
+        }
+    }
+}
+
 pub struct Layer<T> {
     pub children: RefCell<Vec<Rc<Layer<T>>>>,
     pub transform: RefCell<Matrix4<f32>>,
-    pub tile_size: usize,
+
+    /// The dimensions of the tiles that make up this layer's `TileGrid`. Unlike a
+    /// single global tile size, this can be chosen per layer role: large tiles for
+    /// big scrolling surfaces reduce tile count and composite draw calls, while
+    /// small tiles limit wasted repaint for rarely-changing UI chrome.
+    pub tile_size: Cell<Size2D<usize>>,
     pub extra_data: RefCell<T>,
     tile_grid: RefCell<TileGrid>,
 
@@ -49,6 +124,8 @@ pub struct Layer<T> {
     pub bounds: RefCell<TypedRect<LayerPixel, f32>>,
 
     /// A monotonically increasing counter that keeps track of the current content age.
+    /// Used as a whole-layer fallback by callers that don't supply per-tile
+    /// dependencies via `update_tile_dependencies`.
     pub content_age: RefCell<ContentAge>,
 
     /// The content offset for this layer in unscaled layer pixels.
@@ -62,11 +139,19 @@ pub struct Layer<T> {
 
     /// The opacity of this layer, from 0.0 (fully transparent) to 1.0 (fully opaque).
     pub opacity: RefCell<f32>,
+
+    /// If set, this layer is backed by an externally-owned image (typically a
+    /// decoded video frame) that should bypass the RGBA tile pipeline entirely.
+    /// See `CompositorSurface`.
+    pub external_surface: RefCell<Option<CompositorSurface>>,
+
+    /// The screen regions that changed since the last `take_dirty_region`.
+    dirty_region: RefCell<DirtyRegion>,
 }
 
 impl<T> Layer<T> {
     pub fn new(bounds: TypedRect<LayerPixel, f32>,
-               tile_size: usize,
+               tile_size: Size2D<usize>,
                background_color: Color,
                opacity: f32,
                data: T)
@@ -75,7 +160,7 @@ impl<T> Layer<T> {
             children: RefCell::new(vec!()),
             transform: RefCell::new(identity()),
             bounds: RefCell::new(bounds),
-            tile_size: tile_size,
+            tile_size: Cell::new(tile_size),
             extra_data: RefCell::new(data),
             tile_grid: RefCell::new(TileGrid::new(tile_size)),
             content_age: RefCell::new(ContentAge::new()),
@@ -83,6 +168,8 @@ impl<T> Layer<T> {
             content_offset: RefCell::new(Point2D::zero()),
             background_color: RefCell::new(background_color),
             opacity: RefCell::new(opacity),
+            external_surface: RefCell::new(None),
+            dirty_region: RefCell::new(DirtyRegion::new()),
         }
     }
 
@@ -102,14 +189,99 @@ impl<T> Layer<T> {
                                rect_in_layer: TypedRect<LayerPixel, f32>,
                                scale: ScaleFactor<LayerPixel, DevicePixel, f32>)
                                -> Vec<BufferRequest> {
+        // A layer backed by an externally-owned image bypasses the RGBA tile
+        // pipeline entirely; it has nothing for this layer's own TileGrid to
+        // request or repaint.
+        if self.external_surface.borrow().is_some() {
+            return vec!();
+        }
+
         let mut tile_grid = self.tile_grid.borrow_mut();
-        return tile_grid.get_buffer_requests_in_rect(rect_in_layer * scale,
-                                                     self.bounds.borrow().size * scale,
+
+        // Tiles that have never been painted at all are a safe default to
+        // treat as a flat fill of the background color. A tile that already
+        // holds real content from `add_buffer` is left untouched regardless
+        // of whether this layer currently has children, since leaf layers
+        // are exactly where painted content tiles live.
+        tile_grid.mark_unpainted_tiles_solid(rect_in_layer, *self.background_color.borrow());
+
+        return tile_grid.get_buffer_requests_in_rect(rect_in_layer,
+                                                     scale,
                                                      *self.content_age.borrow());
     }
 
+    /// Walks this layer and its children collecting every visible `BufferRequest`,
+    /// clipping against ancestors that set `masks_to_bounds` and dispatching each
+    /// request to `pool` (to be painted with `paint`) so tiles across the whole
+    /// subtree can be painted concurrently instead of one at a time on the
+    /// caller's thread. `rect_in_layer` is in this layer's own coordinate space;
+    /// it's translated into each child's space (by that child's `bounds.origin`)
+    /// before recursing, since a child's tiles are indexed relative to its own
+    /// origin, not its parent's. Before dispatching each request, `buffer_map`
+    /// is checked for a recycled buffer of matching size, so a tile that needs
+    /// repainting can reuse an existing `NativeSurface` instead of the paint
+    /// closure allocating a new one from scratch. A request for a solid-color
+    /// tile never reaches `pool` at all, since it needs no rasterization or
+    /// `NativeSurface` upload; the compositor draws it as a quad straight
+    /// from `request.content`.
+    pub fn get_buffer_requests_in_pool(&self,
+                                        pool: &RenderPool,
+                                        buffer_map: &mut BufferMap,
+                                        rect_in_layer: TypedRect<LayerPixel, f32>,
+                                        scale: ScaleFactor<LayerPixel, DevicePixel, f32>,
+                                        paint: &PaintFn) {
+        // Bypasses the RGBA tile pipeline entirely for a layer backed by an
+        // externally-owned image; nothing here needs to be painted.
+        if self.external_surface.borrow().is_some() {
+            return;
+        }
+
+        let clipped_rect = if *self.masks_to_bounds.borrow() {
+            // `bounds` is in the parent's space; clip against it translated
+            // into this layer's own space.
+            let own_bounds = TypedRect::new(TypedPoint2D::new(0.0, 0.0), self.bounds.borrow().size);
+            match rect_in_layer.intersection(&own_bounds) {
+                Some(rect) => rect,
+                None => return,
+            }
+        } else {
+            rect_in_layer
+        };
+
+        for request in self.get_buffer_requests(clipped_rect, scale) {
+            if let TileContentRequest::Paint = request.content {
+                let recycled = buffer_map.find(request.screen_rect.size);
+                pool.paint_tile(*self.transform.borrow(), request, recycled, paint.clone());
+            }
+        }
+
+        for child in self.children().iter() {
+            let child_bounds = *child.bounds.borrow();
+            if let Some(visible_rect) = clipped_rect.intersection(&child_bounds) {
+                let rect_in_child = TypedRect::new(
+                    TypedPoint2D::new(visible_rect.origin.x - child_bounds.origin.x,
+                                       visible_rect.origin.y - child_bounds.origin.y),
+                    visible_rect.size);
+                child.get_buffer_requests_in_pool(pool, buffer_map, rect_in_child, scale, paint);
+            }
+        }
+    }
+
     pub fn resize(&self, new_size: TypedSize2D<LayerPixel, f32>) {
+        let old_bounds = *self.bounds.borrow();
         self.bounds.borrow_mut().size = new_size;
+        self.dirty_region.borrow_mut().union(old_bounds);
+        self.dirty_region.borrow_mut().union(*self.bounds.borrow());
+    }
+
+    /// Changes the tile geometry used by this layer's `TileGrid`, e.g. to switch a
+    /// large scrolling surface to large tiles or a volatile UI layer to small ones.
+    /// Existing tiles are dropped (their texture buffers go to the unused-buffer
+    /// list) and the layer is re-tiled at the new size on the next
+    /// `get_buffer_requests`.
+    pub fn set_tile_size(&self, tile_size: Size2D<usize>) {
+        self.tile_size.set(tile_size);
+        self.tile_grid.borrow_mut().set_tile_size(tile_size);
     }
 
     pub fn add_buffer(&self, tile: Box<LayerBuffer>) {
@@ -120,23 +292,98 @@ impl<T> Layer<T> {
         self.tile_grid.borrow_mut().take_unused_buffers()
     }
 
+    /// Collects this layer's currently-unused buffers and hands them to
+    /// `buffer_map` for recycling, rather than letting them be dropped and the
+    /// next `get_buffer_requests` reallocate a `NativeSurface` of the same size
+    /// from scratch.
+    pub fn recycle_unused_buffers(&self,
+                                   display: &NativeCompositingGraphicsContext,
+                                   buffer_map: &mut BufferMap) {
+        buffer_map.insert_buffers(display, self.collect_unused_buffers());
+    }
+
     pub fn collect_buffers(&self) -> Vec<Box<LayerBuffer>> {
         self.tile_grid.borrow_mut().collect_buffers()
     }
 
     pub fn contents_changed(&self) {
         self.content_age.borrow_mut().next();
+        let bounds = *self.bounds.borrow();
+        self.dirty_region.borrow_mut().union(bounds);
+    }
+
+    /// Returns the device-pixel rects that changed in this layer since the last
+    /// call, and resets its dirty region. Lets the compositor issue a partial
+    /// present or scissored composite instead of redrawing the whole layer.
+    /// Combines this layer's own whole-layer fallback (from `resize`/
+    /// `contents_changed`) with the finer-grained per-tile rects the tile grid
+    /// accumulated from `update_tile_dependencies`/`mark_tiles_solid`.
+    pub fn take_dirty_region(&self,
+                              scale: ScaleFactor<LayerPixel, DevicePixel, f32>)
+                              -> Vec<Rect<usize>> {
+        let mut rects = self.dirty_region.borrow_mut().take();
+        rects.extend(self.tile_grid.borrow_mut().take_dirty_rects());
+
+        rects.iter().map(|rect| {
+            let scaled = *rect * scale;
+            Rect::new(Point2D::new(scaled.origin.x as usize, scaled.origin.y as usize),
+                      Size2D::new(scaled.size.width as usize, scaled.size.height as usize))
+        }).collect()
+    }
+
+    /// Records the content dependencies that currently affect the tiles overlapping
+    /// `rect`. Each tile's new dependency set is compared right away against the
+    /// one it was last given (order-independent, with a length-changed fast path),
+    /// and only tiles whose dependencies actually changed are marked for repaint by
+    /// the next call to `get_buffer_requests`. A tile that has dependencies recorded this
+    /// way no longer repaints just because `contents_changed` bumped the layer's
+    /// whole-layer content age elsewhere; callers that don't track dependencies
+    /// can keep relying on `contents_changed` instead.
+    pub fn update_tile_dependencies(&self,
+                                     rect: TypedRect<LayerPixel, f32>,
+                                     dependencies: &[Dependency]) {
+        self.tile_grid.borrow_mut().update_dependencies(rect, dependencies);
     }
 
     pub fn create_textures(&self, graphics_context: &NativeCompositingGraphicsContext) {
         self.tile_grid.borrow_mut().create_textures(graphics_context);
     }
 
+    /// Marks this layer as backed by an externally-owned image, or clears that if
+    /// `surface` is `None`. See `CompositorSurface`.
+    pub fn set_external_surface(&self, surface: Option<CompositorSurface>) {
+        *self.external_surface.borrow_mut() = surface;
+    }
+
+    /// Returns the externally-owned image backing this layer, if any.
+    pub fn external_surface(&self) -> Option<CompositorSurface> {
+        self.external_surface.borrow().clone()
+    }
+
+    /// Marks the tiles overlapping `rect` as a solid fill of `color`, e.g. because
+    /// this layer's `background_color` covers them and no other content intersects
+    /// them. The next `get_buffer_requests` will emit a `TileContentRequest::Color`
+    /// for those tiles instead of a full rasterization request.
+    pub fn mark_tiles_solid(&self, rect: TypedRect<LayerPixel, f32>, color: Color) {
+        self.tile_grid.borrow_mut().mark_solid(rect, color);
+    }
+
     pub fn do_for_all_tiles<F: Fn(&Tile)>(&self, f: F) {
         self.tile_grid.borrow().do_for_all_tiles(f);
     }
 }
 
+/// What a `BufferRequest` is asking the renderer to produce.
+#[derive(Clone, Copy)]
+pub enum TileContentRequest {
+    /// The tile must be rasterized into a texture as usual.
+    Paint,
+
+    /// The tile is known to be a uniform fill, so it can be satisfied with a
+    /// colored quad instead of allocating and uploading a `NativeSurface`.
+    Color(Color),
+}
+
 /// A request from the compositor to the renderer for tiles that need to be (re)displayed.
 #[derive(Clone, Copy)]
 pub struct BufferRequest {
@@ -148,6 +395,10 @@ pub struct BufferRequest {
 
     /// The content age of that this BufferRequest corresponds to.
     pub content_age: ContentAge,
+
+    /// Whether this request wants a rasterized tile or can be satisfied with a
+    /// solid-color quad. See `TileContentRequest`.
+    pub content: TileContentRequest,
 }
 
 impl BufferRequest {
@@ -159,10 +410,81 @@ impl BufferRequest {
             screen_rect: screen_rect,
             page_rect: page_rect,
             content_age: content_age,
+            content: TileContentRequest::Paint,
+        }
+    }
+
+    /// Creates a lightweight request for a "clear tile": a tile whose content is a
+    /// single uniform color, which the render path can fill with a colored quad
+    /// rather than uploading a texture.
+    pub fn new_color(screen_rect: Rect<usize>,
+                      page_rect: Rect<f32>,
+                      content_age: ContentAge,
+                      color: Color)
+                      -> BufferRequest {
+        BufferRequest {
+            screen_rect: screen_rect,
+            page_rect: page_rect,
+            content_age: content_age,
+            content: TileContentRequest::Color(color),
         }
     }
 }
 
+/// The content that backs a single tile, following the same rasterized-texture vs.
+/// clear-tile distinction as `TileContentRequest`.
+pub enum TileSurface {
+    /// A tile rasterized into a texture-backed `LayerBuffer`.
+    Texture(Box<LayerBuffer>),
+
+    /// A tile known to be a single uniform color, composited as a solid quad.
+    Color(Color),
+}
+
+/// The packed pixel layout of an externally-owned YUV image, as reported by a
+/// video decoder.
+#[derive(Clone, Copy, PartialEq)]
+pub enum YuvFormat {
+    NV12,
+    I420,
+    YV12,
+}
+
+/// The color space and sample range of a YUV surface.
+#[derive(Clone, Copy, PartialEq)]
+pub enum YuvColorSpace {
+    Rec601,
+    Rec709,
+    Rec2020,
+}
+
+/// A `YuvColorSpace` together with whether it uses the full (0-255) or studio-swing
+/// sample range, mirroring the pair a decoder reports alongside its pixel format.
+#[derive(Clone, Copy, PartialEq)]
+pub struct YuvRangedColorSpace {
+    pub color_space: YuvColorSpace,
+    pub full_range: bool,
+}
+
+/// Whether a `CompositorSurface` should be drawn as a normal textured primitive or
+/// handed off to the platform as a native overlay plane.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CompositorSurfaceKind {
+    Blit,
+    Overlay,
+}
+
+/// An image owned and updated outside of this crate, most commonly a decoded video
+/// frame, that a `Layer` can composite directly instead of treating it as a
+/// pre-converted RGBA `LayerBuffer`. The render path samples and color-converts the
+/// YUV planes in-shader, or hands the surface to an OS overlay, according to `kind`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct CompositorSurface {
+    pub format: YuvFormat,
+    pub color_space: YuvRangedColorSpace,
+    pub kind: CompositorSurfaceKind,
+}
+
 pub struct LayerBuffer {
     /// The native surface which can be shared between threads or processes. On Mac this is an
     /// `IOSurface`; on Linux this is an X Pixmap; on Android this is an `EGLImageKHR`.
@@ -231,3 +553,50 @@ impl LayerBufferSet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{DirtyRegion, MAX_DIRTY_SUB_RECTS};
+    use geometry::LayerPixel;
+
+    use geom::point::TypedPoint2D;
+    use geom::rect::TypedRect;
+    use geom::size::TypedSize2D;
+
+    fn rect_at(x: f32, y: f32) -> TypedRect<LayerPixel, f32> {
+        TypedRect::new(TypedPoint2D::new(x, y), TypedSize2D::new(1.0, 1.0))
+    }
+
+    #[test]
+    fn take_returns_one_rect_per_union_under_the_cap() {
+        let mut region = DirtyRegion::new();
+        region.union(rect_at(0.0, 0.0));
+        region.union(rect_at(5.0, 5.0));
+        assert_eq!(region.take().len(), 2);
+    }
+
+    #[test]
+    fn take_resets_the_region_to_empty() {
+        let mut region = DirtyRegion::new();
+        region.union(rect_at(0.0, 0.0));
+        region.take();
+        assert_eq!(region.take().len(), 0);
+    }
+
+    #[test]
+    fn take_collapses_to_the_bounding_rect_past_the_cap() {
+        let mut region = DirtyRegion::new();
+        for i in 0..(MAX_DIRTY_SUB_RECTS + 1) {
+            region.union(rect_at(i as f32, i as f32));
+        }
+
+        let rects = region.take();
+        assert_eq!(rects.len(), 1);
+
+        let bounds = rects[0];
+        assert_eq!(bounds.origin.x, 0.0);
+        assert_eq!(bounds.origin.y, 0.0);
+        assert_eq!(bounds.size.width, MAX_DIRTY_SUB_RECTS as f32 + 1.0);
+        assert_eq!(bounds.size.height, MAX_DIRTY_SUB_RECTS as f32 + 1.0);
+    }
+}
@@ -0,0 +1,18 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate color;
+extern crate geom;
+extern crate geometry;
+extern crate platform;
+
+pub mod buffer_map;
+pub mod layers;
+pub mod render_pool;
+pub mod tiling;
@@ -0,0 +1,108 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use layers::{BufferRequest, LayerBuffer};
+
+use geom::matrix::Matrix4;
+use platform::surface::NativePaintingGraphicsContext;
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+
+/// Paints a single tile's `BufferRequest` into a `LayerBuffer`, given the
+/// worker's own `NativePaintingGraphicsContext` and, if the caller found one
+/// of matching size in its `BufferMap`, a recycled buffer whose
+/// `NativeSurface` can be reused instead of allocating a new one. Supplied by
+/// the renderer, which knows how to rasterize page content; `RenderPool`
+/// only knows how to schedule that work across its workers.
+pub type PaintFn = Arc<Fn(Matrix4<f32>, BufferRequest, Option<Box<LayerBuffer>>, &NativePaintingGraphicsContext)
+                          -> Box<LayerBuffer>
+                          + Send + Sync>;
+
+struct PaintJob {
+    transform: Matrix4<f32>,
+    request: BufferRequest,
+    recycled: Option<Box<LayerBuffer>>,
+    paint: PaintFn,
+}
+
+/// A pool of worker threads that paint tiles concurrently instead of one at a
+/// time on the caller's thread. Each worker owns its own
+/// `NativePaintingGraphicsContext` for its whole lifetime, so that
+/// `NativeSurface` creation and `mark_wont_leak` happen on the thread that
+/// goes on to use them, as the platform surface APIs require.
+pub struct RenderPool {
+    job_sender: Sender<PaintJob>,
+    result_receiver: Receiver<Box<LayerBuffer>>,
+}
+
+impl RenderPool {
+    pub fn new(worker_count: usize) -> RenderPool {
+        let (job_sender, job_receiver): (Sender<PaintJob>, Receiver<PaintJob>) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, result_receiver) = channel();
+
+        for _ in 0..worker_count {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            thread::spawn(move || {
+                let graphics_context = NativePaintingGraphicsContext::new();
+                loop {
+                    let job = job_receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => {
+                            let mut buffer = (job.paint)(job.transform, job.request, job.recycled, &graphics_context);
+
+                            // This has to happen here, on the worker thread
+                            // that owns the NativeSurface's context, rather
+                            // than later wherever the result ends up being
+                            // collected -- by the time collect_painted_buffers
+                            // is called, there's no way to tell which worker
+                            // produced a given buffer.
+                            buffer.mark_wont_leak();
+
+                            if result_sender.send(buffer).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+
+        RenderPool {
+            job_sender: job_sender,
+            result_receiver: result_receiver,
+        }
+    }
+
+    /// Schedules `request` to be painted by `paint` on whichever worker picks
+    /// it up next. `recycled`, if supplied, is a buffer of matching size the
+    /// caller found in its `BufferMap`, which `paint` can reuse instead of
+    /// allocating a new `NativeSurface`.
+    pub fn paint_tile(&self,
+                       transform: Matrix4<f32>,
+                       request: BufferRequest,
+                       recycled: Option<Box<LayerBuffer>>,
+                       paint: PaintFn) {
+        let _ = self.job_sender.send(PaintJob {
+            transform: transform,
+            request: request,
+            recycled: recycled,
+            paint: paint,
+        });
+    }
+
+    /// Returns the buffers painted so far without blocking for more.
+    pub fn collect_painted_buffers(&self) -> Vec<Box<LayerBuffer>> {
+        self.result_receiver.try_iter().collect()
+    }
+}
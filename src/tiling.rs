@@ -0,0 +1,334 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use color::Color;
+use geometry::{DevicePixel, LayerPixel};
+use layers::{BufferRequest, ContentAge, Dependency, DirtyRegion, LayerBuffer, TileSurface};
+
+use geom::point::{Point2D, TypedPoint2D};
+use geom::rect::{Rect, TypedRect};
+use geom::scale_factor::ScaleFactor;
+use geom::size::{Size2D, TypedSize2D};
+use platform::surface::NativeCompositingGraphicsContext;
+
+use std::collections::HashMap;
+use std::mem;
+
+/// The column/row coordinates of a single tile within a `TileGrid`.
+pub type TileIndex = (isize, isize);
+
+/// A single tile of a `TileGrid`. Tracks enough frame-over-frame state to
+/// decide whether it actually needs to be repainted, rather than assuming
+/// the whole layer changed whenever anything does.
+pub struct Tile {
+    /// This tile's rect in the layer's own (unscaled) coordinate space.
+    pub rect: TypedRect<LayerPixel, f32>,
+
+    /// What currently backs this tile, if it has been painted at all.
+    surface: Option<TileSurface>,
+
+    /// The content age this tile was last invalidated at. Used as the
+    /// whole-layer fallback for callers that never call `update_dependencies`.
+    content_age: ContentAge,
+
+    /// The dependency hashes recorded the last time this tile was invalidated.
+    dependencies: Vec<Dependency>,
+
+    /// Whether this tile needs to be repainted before it can satisfy a
+    /// `BufferRequest`.
+    dirty: bool,
+}
+
+impl Tile {
+    fn new(rect: TypedRect<LayerPixel, f32>) -> Tile {
+        Tile {
+            rect: rect,
+            surface: None,
+            content_age: ContentAge::new(),
+            dependencies: vec!(),
+            dirty: true,
+        }
+    }
+
+    /// Compares `new_dependencies` against the set recorded the last time this
+    /// tile was invalidated. A changed length is always a difference (the
+    /// fast path); otherwise this is an order-independent comparison, since
+    /// re-ordering the primitives that touch a tile doesn't change how it
+    /// looks.
+    fn dependencies_changed(&self, new_dependencies: &[Dependency]) -> bool {
+        if self.dependencies.len() != new_dependencies.len() {
+            return true;
+        }
+
+        new_dependencies.iter().any(|dependency| !self.dependencies.contains(dependency))
+    }
+
+    fn buffer_request(&self, scale: ScaleFactor<LayerPixel, DevicePixel, f32>, content_age: ContentAge)
+                       -> BufferRequest {
+        let scaled = self.rect * scale;
+        let screen_rect = Rect::new(Point2D::new(scaled.origin.x as usize, scaled.origin.y as usize),
+                                     Size2D::new(scaled.size.width as usize, scaled.size.height as usize));
+        let page_rect = Rect::new(Point2D::new(self.rect.origin.x, self.rect.origin.y),
+                                   Size2D::new(self.rect.size.width, self.rect.size.height));
+
+        match self.surface {
+            Some(TileSurface::Color(color)) => {
+                BufferRequest::new_color(screen_rect, page_rect, content_age, color)
+            }
+            _ => BufferRequest::new(screen_rect, page_rect, content_age),
+        }
+    }
+}
+
+/// A grid of fixed-size tiles covering a `Layer`'s visible area. Tiles are
+/// indexed in the layer's own unscaled coordinate space, so that a layer's
+/// dependency tracking and its device-pixel buffer requests share the same
+/// tile geometry regardless of the current device scale.
+pub struct TileGrid {
+    tile_size: Size2D<usize>,
+    tiles: HashMap<TileIndex, Tile>,
+    unused_buffers: Vec<Box<LayerBuffer>>,
+
+    /// The rects of the tiles actually invalidated since the last
+    /// `take_dirty_rects`, as opposed to a caller's whole-layer fallback
+    /// (`Layer::contents_changed`/`resize`), which union the full bounds
+    /// because they have no finer-grained information to go on.
+    dirty_region: DirtyRegion,
+}
+
+impl TileGrid {
+    pub fn new(tile_size: Size2D<usize>) -> TileGrid {
+        TileGrid {
+            tile_size: tile_size,
+            tiles: HashMap::new(),
+            unused_buffers: vec!(),
+            dirty_region: DirtyRegion::new(),
+        }
+    }
+
+    /// Switches to a new tile geometry. Tiles built under the old geometry no
+    /// longer line up with `indices_for_rect`, so they're dropped outright;
+    /// their buffers are recycled into `unused_buffers` rather than leaked,
+    /// and the next `get_buffer_requests_in_rect` re-tiles the layer from
+    /// scratch at the new size.
+    pub fn set_tile_size(&mut self, tile_size: Size2D<usize>) {
+        self.tile_size = tile_size;
+        for (_, mut tile) in self.tiles.drain() {
+            if let Some(TileSurface::Texture(buffer)) = tile.surface.take() {
+                self.unused_buffers.push(buffer);
+            }
+        }
+    }
+
+    fn index_for_point(&self, x: f32, y: f32) -> TileIndex {
+        ((x / self.tile_size.width as f32).floor() as isize,
+         (y / self.tile_size.height as f32).floor() as isize)
+    }
+
+    fn indices_for_rect(&self, rect: TypedRect<LayerPixel, f32>) -> Vec<TileIndex> {
+        let top_left = self.index_for_point(rect.origin.x, rect.origin.y);
+        // The rect's far edges are exclusive, so a rect ending exactly on a tile
+        // boundary doesn't pull in the tile that only starts there.
+        let bottom_right = (((rect.origin.x + rect.size.width) / self.tile_size.width as f32).ceil() as isize - 1,
+                            ((rect.origin.y + rect.size.height) / self.tile_size.height as f32).ceil() as isize - 1);
+
+        let mut indices = vec!();
+        let mut y = top_left.1;
+        while y <= bottom_right.1 {
+            let mut x = top_left.0;
+            while x <= bottom_right.0 {
+                indices.push((x, y));
+                x += 1;
+            }
+            y += 1;
+        }
+        indices
+    }
+
+    fn rect_for_index(&self, index: TileIndex) -> TypedRect<LayerPixel, f32> {
+        let origin_x = index.0 * self.tile_size.width as isize;
+        let origin_y = index.1 * self.tile_size.height as isize;
+        TypedRect::new(TypedPoint2D::new(origin_x as f32, origin_y as f32),
+                        TypedSize2D::new(self.tile_size.width as f32, self.tile_size.height as f32))
+    }
+
+    fn tile_mut(&mut self, index: TileIndex) -> &mut Tile {
+        let rect = self.rect_for_index(index);
+        self.tiles.entry(index).or_insert_with(|| Tile::new(rect))
+    }
+
+    /// Returns the `BufferRequest`s needed to display `rect_in_layer`,
+    /// skipping any tile that hasn't actually changed. A tile that has
+    /// dependencies recorded via `update_dependencies` repaints only when
+    /// `dirty` (set when those dependencies actually changed, or by
+    /// `mark_solid`) -- a `content_age` bump elsewhere in the layer doesn't
+    /// force it to repaint, which is the whole point of tracking per-tile
+    /// dependencies. A tile with no recorded dependencies has no finer-grained
+    /// signal to go on, so it falls back to the whole-layer `content_age`
+    /// comparison.
+    pub fn get_buffer_requests_in_rect(&mut self,
+                                        rect_in_layer: TypedRect<LayerPixel, f32>,
+                                        scale: ScaleFactor<LayerPixel, DevicePixel, f32>,
+                                        content_age: ContentAge)
+                                        -> Vec<BufferRequest> {
+        let mut requests = vec!();
+        for index in self.indices_for_rect(rect_in_layer) {
+            let needs_repaint = match self.tiles.get(&index) {
+                Some(tile) => {
+                    tile.dirty || (tile.dependencies.is_empty() && tile.content_age != content_age)
+                }
+                None => true,
+            };
+
+            if !needs_repaint {
+                continue;
+            }
+
+            let tile = self.tile_mut(index);
+            requests.push(tile.buffer_request(scale, content_age));
+            tile.content_age = content_age;
+            tile.dirty = false;
+        }
+        requests
+    }
+
+    /// Records the dependencies that currently affect the tiles overlapping
+    /// `rect_in_layer`. Any tile whose dependency set changed (or which
+    /// doesn't exist yet) is marked dirty so the next
+    /// `get_buffer_requests_in_rect` repaints it; tiles whose dependencies
+    /// are unchanged are left alone even if `content_age` has advanced
+    /// elsewhere in the layer.
+    pub fn update_dependencies(&mut self, rect_in_layer: TypedRect<LayerPixel, f32>, dependencies: &[Dependency]) {
+        for index in self.indices_for_rect(rect_in_layer) {
+            let changed = {
+                let tile = self.tile_mut(index);
+                let changed = tile.dependencies_changed(dependencies);
+                tile.dependencies = dependencies.to_vec();
+                if changed {
+                    tile.dirty = true;
+                }
+                changed
+            };
+
+            if changed {
+                let rect = self.rect_for_index(index);
+                self.dirty_region.union(rect);
+            }
+        }
+    }
+
+    /// Marks the tiles overlapping `rect_in_layer` as a solid fill of `color`,
+    /// for a caller that positively knows those tiles are uncovered (e.g. a
+    /// compositor tracking real paint coverage). Any existing `Texture`
+    /// surface that gets replaced is recycled into `unused_buffers` rather
+    /// than dropped in place, the same as every other surface-replacement
+    /// site in this grid.
+    pub fn mark_solid(&mut self, rect_in_layer: TypedRect<LayerPixel, f32>, color: Color) {
+        for index in self.indices_for_rect(rect_in_layer) {
+            self.mark_solid_index(index, color);
+        }
+    }
+
+    /// Marks as a solid fill of `color` only the tiles overlapping
+    /// `rect_in_layer` that have never been painted at all (no surface of
+    /// any kind yet). This is the one generically safe "nothing else paints
+    /// this tile" signal available at the grid level: a layer having no
+    /// children says nothing about whether a given tile already holds real
+    /// content, since leaf layers are exactly where painted content tiles
+    /// come from (`add_buffer`). A tile already holding a `Texture` or a
+    /// previously-set `Color` is left completely untouched.
+    pub fn mark_unpainted_tiles_solid(&mut self, rect_in_layer: TypedRect<LayerPixel, f32>, color: Color) {
+        for index in self.indices_for_rect(rect_in_layer) {
+            let already_painted = match self.tiles.get(&index) {
+                Some(tile) => tile.surface.is_some(),
+                None => false,
+            };
+
+            if !already_painted {
+                self.mark_solid_index(index, color);
+            }
+        }
+    }
+
+    fn mark_solid_index(&mut self, index: TileIndex, color: Color) {
+        let (became_dirty, replaced_texture) = {
+            let tile = self.tile_mut(index);
+            let already_solid = match tile.surface {
+                Some(TileSurface::Color(existing)) => existing == color,
+                _ => false,
+            };
+
+            let mut replaced_texture = None;
+            if !already_solid {
+                tile.dirty = true;
+                if let Some(TileSurface::Texture(buffer)) = tile.surface.take() {
+                    replaced_texture = Some(buffer);
+                }
+                tile.surface = Some(TileSurface::Color(color));
+            }
+
+            (!already_solid, replaced_texture)
+        };
+
+        if let Some(buffer) = replaced_texture {
+            self.unused_buffers.push(buffer);
+        }
+
+        if became_dirty {
+            let rect = self.rect_for_index(index);
+            self.dirty_region.union(rect);
+        }
+    }
+
+    /// Returns the tile rects actually invalidated (via `update_dependencies` or
+    /// `mark_solid`) since the last call, resetting the region to empty. Used by
+    /// `Layer::take_dirty_region` alongside its own whole-layer fallback.
+    pub fn take_dirty_rects(&mut self) -> Vec<TypedRect<LayerPixel, f32>> {
+        self.dirty_region.take()
+    }
+
+    pub fn add_buffer(&mut self, buffer: Box<LayerBuffer>) {
+        let index = self.index_for_point(buffer.rect.origin.x, buffer.rect.origin.y);
+        let old_surface = {
+            let tile = self.tile_mut(index);
+            let old_surface = tile.surface.take();
+            tile.surface = Some(TileSurface::Texture(buffer));
+            old_surface
+        };
+
+        if let Some(TileSurface::Texture(old_buffer)) = old_surface {
+            self.unused_buffers.push(old_buffer);
+        }
+    }
+
+    pub fn take_unused_buffers(&mut self) -> Vec<Box<LayerBuffer>> {
+        mem::replace(&mut self.unused_buffers, vec!())
+    }
+
+    pub fn collect_buffers(&mut self) -> Vec<Box<LayerBuffer>> {
+        let mut buffers = self.take_unused_buffers();
+        for tile in self.tiles.values_mut() {
+            if let Some(TileSurface::Texture(buffer)) = tile.surface.take() {
+                buffers.push(buffer);
+            }
+        }
+        buffers
+    }
+
+    pub fn create_textures(&mut self, _graphics_context: &NativeCompositingGraphicsContext) {
+        // Texture upload is handled by the platform-specific compositing
+        // backend; there's nothing tile-grid-specific to do here.
+    }
+
+    pub fn do_for_all_tiles<F: Fn(&Tile)>(&self, f: F) {
+        for tile in self.tiles.values() {
+            f(tile);
+        }
+    }
+}